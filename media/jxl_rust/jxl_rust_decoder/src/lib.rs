@@ -35,13 +35,398 @@ impl std::fmt::Debug for DecoderState {
 }
 
 /// Cached image information for C++ access
+///
+/// `width`/`height` are the final, post-orientation dimensions: the same
+/// dimensions `decode_frame`/`flush_preview` write their output buffers at.
 #[derive(Clone)]
 pub struct CachedImageInfo {
     pub width: u32,
     pub height: u32,
     pub has_alpha: bool,
-    pub orientation_transpose: bool,
     pub is_grayscale: bool,
+    // Animation metadata, absent for still images
+    pub tps_numerator: u32,
+    pub tps_denominator: u32,
+    pub num_loops: u32,
+    // Color encoding, so the compositor can tone-map or pass through to an
+    // HDR surface instead of the content being silently crushed to SDR.
+    pub transfer_function: TransferFunction,
+    pub primaries: ColorPrimaries,
+    pub is_hdr: bool,
+}
+
+/// Transfer function (gamma/EOTF) of an image's color encoding, mirroring
+/// `jxl::api`'s transfer function enum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferFunction {
+    Srgb,
+    Linear,
+    Gamma,
+    /// SMPTE ST 2084 (Perceptual Quantizer), used for HDR content.
+    Pq,
+    /// Hybrid Log-Gamma, used for HDR content.
+    Hlg,
+    Unknown,
+}
+
+impl TransferFunction {
+    fn from_jxl(transfer_function: jxl::api::TransferFunction) -> Self {
+        match transfer_function {
+            jxl::api::TransferFunction::Srgb => TransferFunction::Srgb,
+            jxl::api::TransferFunction::Linear => TransferFunction::Linear,
+            jxl::api::TransferFunction::Gamma(_) => TransferFunction::Gamma,
+            jxl::api::TransferFunction::Pq => TransferFunction::Pq,
+            jxl::api::TransferFunction::Hlg => TransferFunction::Hlg,
+            jxl::api::TransferFunction::Dci | jxl::api::TransferFunction::Unknown => {
+                TransferFunction::Unknown
+            }
+        }
+    }
+
+    fn is_hdr(self) -> bool {
+        matches!(self, TransferFunction::Pq | TransferFunction::Hlg)
+    }
+}
+
+/// Color primaries of an image's color encoding, mirroring `jxl::api`'s
+/// primaries enum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorPrimaries {
+    Srgb,
+    Rec2020,
+    P3,
+    Custom,
+}
+
+impl ColorPrimaries {
+    fn from_jxl(primaries: jxl::api::Primaries) -> Self {
+        match primaries {
+            jxl::api::Primaries::Srgb => ColorPrimaries::Srgb,
+            jxl::api::Primaries::Rec2020 => ColorPrimaries::Rec2020,
+            jxl::api::Primaries::P3 => ColorPrimaries::P3,
+            jxl::api::Primaries::Custom(_) => ColorPrimaries::Custom,
+        }
+    }
+}
+
+/// Output pixel format selected via `JxlRustDecoder::set_output_format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// 8-bit BGRA packed into a u32 per pixel (the default).
+    Bgra8,
+    /// 16-bit RGBA, four u16 per pixel spanning the full 0..65535 range.
+    Rgba16,
+    /// Half-float (IEEE 754 binary16) RGBA, four u16 bit-patterns per pixel,
+    /// unclamped so HDR values above 1.0 survive.
+    Rgba16Float,
+}
+
+/// How a decoded frame's pixels combine with the persistent canvas.
+///
+/// Mirrors the JXL bitstream's `BlendMode` (ISO/IEC 18181-1 7.2.5.2).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BlendMode {
+    /// Overwrite the crop region with the frame's pixels.
+    Replace,
+    /// Add the frame's pixels to the canvas.
+    Add,
+    /// Source-over alpha compositing: `out = src + dst * (1 - src_a)`.
+    Blend,
+    /// Add the frame's pixels, scaled by the frame's alpha, to the canvas.
+    MulAdd,
+}
+
+impl BlendMode {
+    fn from_frame_header(blend_mode: jxl::api::BlendMode) -> Self {
+        match blend_mode {
+            jxl::api::BlendMode::Replace => BlendMode::Replace,
+            jxl::api::BlendMode::Add => BlendMode::Add,
+            jxl::api::BlendMode::Blend => BlendMode::Blend,
+            jxl::api::BlendMode::MulAdd => BlendMode::MulAdd,
+        }
+    }
+}
+
+/// The image's EXIF/JXL orientation (`basic_info.orientation`), i.e. how the
+/// decoded, native-resolution pixels must be rotated/mirrored to be upright.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Orientation {
+    Identity,
+    FlipHorizontal,
+    Rotate180,
+    FlipVertical,
+    Transpose,
+    Rotate90,
+    AntiTranspose,
+    Rotate270,
+}
+
+impl Orientation {
+    fn from_basic_info(orientation: jxl::api::Orientation) -> Self {
+        match orientation {
+            jxl::api::Orientation::Identity => Orientation::Identity,
+            jxl::api::Orientation::FlipHorizontal => Orientation::FlipHorizontal,
+            jxl::api::Orientation::Rotate180 => Orientation::Rotate180,
+            jxl::api::Orientation::FlipVertical => Orientation::FlipVertical,
+            jxl::api::Orientation::Transpose => Orientation::Transpose,
+            jxl::api::Orientation::Rotate90 => Orientation::Rotate90,
+            jxl::api::Orientation::AntiTranspose => Orientation::AntiTranspose,
+            jxl::api::Orientation::Rotate270 => Orientation::Rotate270,
+        }
+    }
+
+    /// Whether this orientation swaps width and height (the four orientations
+    /// that involve a 90-degree rotation).
+    fn is_transposing(self) -> bool {
+        matches!(
+            self,
+            Orientation::Transpose
+                | Orientation::Rotate90
+                | Orientation::AntiTranspose
+                | Orientation::Rotate270
+        )
+    }
+
+    /// Map a pixel at `(x, y)` in a native-orientation `width` x `height` buffer
+    /// to its `(x, y)` coordinate in the oriented output buffer.
+    fn map(self, width: usize, height: usize, x: usize, y: usize) -> (usize, usize) {
+        match self {
+            Orientation::Identity => (x, y),
+            Orientation::FlipHorizontal => (width - 1 - x, y),
+            Orientation::Rotate180 => (width - 1 - x, height - 1 - y),
+            Orientation::FlipVertical => (x, height - 1 - y),
+            Orientation::Transpose => (y, x),
+            Orientation::Rotate90 => (height - 1 - y, x),
+            Orientation::AntiTranspose => (height - 1 - y, width - 1 - x),
+            Orientation::Rotate270 => (y, width - 1 - x),
+        }
+    }
+}
+
+/// Rendering intent used when building a qcms `Transform` from an embedded ICC
+/// profile to the output (sRGB) space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RenderingIntent {
+    Perceptual,
+    Relative,
+    Saturation,
+    Absolute,
+}
+
+impl RenderingIntent {
+    fn to_qcms(self) -> Intent {
+        match self {
+            RenderingIntent::Perceptual => Intent::Perceptual,
+            RenderingIntent::Relative => Intent::RelativeColorimetric,
+            RenderingIntent::Saturation => Intent::Saturation,
+            RenderingIntent::Absolute => Intent::AbsoluteColorimetric,
+        }
+    }
+}
+
+/// Small LRU cache of compiled qcms `Transform`s, keyed by a hash of the input
+/// ICC bytes, rendering intent, and channel count. Building a `Transform` is
+/// expensive and many tiles/frames share the same embedded profile.
+struct TransformCache {
+    // Most recently used entry is last.
+    entries: Vec<(u64, Transform)>,
+}
+
+impl TransformCache {
+    const CAPACITY: usize = 12;
+
+    fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    fn get(&mut self, key: u64) -> Option<&Transform> {
+        let pos = self.entries.iter().position(|(k, _)| *k == key)?;
+        let entry = self.entries.remove(pos);
+        self.entries.push(entry);
+        self.entries.last().map(|(_, transform)| transform)
+    }
+
+    fn insert(&mut self, key: u64, transform: Transform) {
+        if self.entries.len() >= Self::CAPACITY {
+            self.entries.remove(0);
+        }
+        self.entries.push((key, transform));
+    }
+}
+
+fn transform_cache_key(icc_data: &[u8], intent: RenderingIntent, num_color_channels: usize) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    icc_data.hash(&mut hasher);
+    intent.hash(&mut hasher);
+    num_color_channels.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// How much of a frame must be decoded before `process_data` will report the
+/// preview as ready. Mirrors the detail levels libjxl exposes for progressive
+/// rendering.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressiveDetail {
+    /// Only the low-frequency (1:8 resolution) DC pass.
+    Dc,
+    /// Every pass as it streams in.
+    AllPasses,
+}
+
+/// Per-frame placement and timing, cached once a frame header has been parsed.
+#[derive(Clone, Copy)]
+struct FrameInfo {
+    x0: usize,
+    y0: usize,
+    width: usize,
+    height: usize,
+    blend_mode: BlendMode,
+    // Which of the 4 reference-frame buffer slots this frame blends against.
+    // We only keep a single running canvas (slot 0); anything else is rejected
+    // rather than silently composited against the wrong pixels.
+    blend_source: u8,
+    duration_ticks: u32,
+    is_last: bool,
+}
+
+/// Which metadata box a `MetadataBoxWalker` is currently inside.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BoxKind {
+    Exif,
+    Xmp,
+    Other,
+}
+
+/// Where a `MetadataBoxWalker` is within the box it's currently parsing.
+enum BoxWalkerState {
+    /// Accumulating the (possibly partial, across `feed` calls) box header:
+    /// a 4-byte size, a 4-byte type, and if the size field reads `1`, an
+    /// extra 8-byte extended size.
+    Header(Vec<u8>),
+    /// Copying (or discarding) the payload of a box of the given kind.
+    /// `remaining == None` means the box's size was 0, i.e. "to EOF".
+    Payload { kind: BoxKind, remaining: Option<u64> },
+    /// Either a bare codestream (no container, nothing to walk) or the
+    /// signature didn't match a JXL container at all.
+    Done,
+}
+
+/// Independently walks a JXL container's ISOBMFF-style box structure to pull
+/// out `Exif` and `xml ` (XMP) metadata box payloads, mirroring the
+/// incremental, call-by-call flow of `process_data`: boxes routinely span
+/// multiple `feed` calls, so all parsing state is carried between them.
+/// Never consumes from the data it's fed; `process_data` separately hands
+/// the same bytes to the `jxl` crate to decode.
+struct MetadataBoxWalker {
+    state: BoxWalkerState,
+    checked_signature: bool,
+    /// Bytes of the container signature seen so far, across `feed` calls,
+    /// until there are enough to tell a container from a bare codestream.
+    signature_buf: Vec<u8>,
+    exif: Option<Vec<u8>>,
+    xmp: Option<Vec<u8>>,
+}
+
+impl MetadataBoxWalker {
+    fn new() -> Self {
+        Self {
+            state: BoxWalkerState::Header(Vec::new()),
+            checked_signature: false,
+            signature_buf: Vec::new(),
+            exif: None,
+            xmp: None,
+        }
+    }
+
+    fn feed(&mut self, mut data: &[u8]) {
+        if !self.checked_signature {
+            // Each call only gets the new incremental chunk, so the
+            // signature itself may be split across calls; accumulate until
+            // there's enough to compare rather than discarding a short chunk.
+            let take =
+                (JXL_CONTAINER_SIGNATURE.len() - self.signature_buf.len()).min(data.len());
+            self.signature_buf.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.signature_buf.len() < JXL_CONTAINER_SIGNATURE.len() {
+                // Wait for enough bytes to tell container from bare codestream.
+                return;
+            }
+            self.checked_signature = true;
+            if self.signature_buf != JXL_CONTAINER_SIGNATURE {
+                // A bare codestream (or anything else) has no boxes to walk.
+                self.state = BoxWalkerState::Done;
+                return;
+            }
+        }
+
+        loop {
+            match &mut self.state {
+                BoxWalkerState::Done => return,
+                BoxWalkerState::Header(partial) => {
+                    let take = (8 - partial.len().min(8)).min(data.len());
+                    partial.extend_from_slice(&data[..take]);
+                    data = &data[take..];
+                    if partial.len() < 8 {
+                        return;
+                    }
+                    let size32 = u32::from_be_bytes(partial[0..4].try_into().unwrap());
+                    let want = if size32 == 1 { 16 } else { 8 };
+                    if partial.len() < want {
+                        let take = (want - partial.len()).min(data.len());
+                        partial.extend_from_slice(&data[..take]);
+                        data = &data[take..];
+                        if partial.len() < want {
+                            return;
+                        }
+                    }
+
+                    let header = std::mem::take(partial);
+                    let kind = match &header[4..8] {
+                        b"Exif" => BoxKind::Exif,
+                        b"xml " => BoxKind::Xmp,
+                        _ => BoxKind::Other,
+                    };
+                    let (box_size, header_len): (Option<u64>, u64) = if size32 == 1 {
+                        (
+                            Some(u64::from_be_bytes(header[8..16].try_into().unwrap())),
+                            16,
+                        )
+                    } else if size32 == 0 {
+                        (None, 8)
+                    } else {
+                        (Some(size32 as u64), 8)
+                    };
+                    let remaining = box_size.map(|total| total.saturating_sub(header_len));
+                    self.state = BoxWalkerState::Payload { kind, remaining };
+                }
+                BoxWalkerState::Payload { kind, remaining } => {
+                    if data.is_empty() {
+                        return;
+                    }
+                    let take = remaining
+                        .map(|r| (r as usize).min(data.len()))
+                        .unwrap_or(data.len());
+                    let (chunk, rest) = data.split_at(take);
+                    match *kind {
+                        BoxKind::Exif => self.exif.get_or_insert_with(Vec::new).extend_from_slice(chunk),
+                        BoxKind::Xmp => self.xmp.get_or_insert_with(Vec::new).extend_from_slice(chunk),
+                        BoxKind::Other => {}
+                    }
+                    data = rest;
+                    *remaining = remaining.map(|r| r - take as u64);
+                    if *remaining == Some(0) {
+                        self.state = BoxWalkerState::Header(Vec::new());
+                    }
+                    if data.is_empty() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
 }
 
 pub struct JxlRustDecoder {
@@ -49,14 +434,50 @@ pub struct JxlRustDecoder {
     pub cached_info: Option<CachedImageInfo>,
     pub frame_ready: bool,
     decoded_pixels: Option<Vec<u32>>,
-    // Persistent buffers for frame decoding
+    // 16-bit/half-float RGBA decode output, populated instead of `decoded_pixels`
+    // when `output_format` is not `OutputFormat::Bgra8`. Four u16 per pixel.
+    decoded_pixels_wide: Option<Vec<u16>>,
+    output_format: OutputFormat,
+    // Persistent buffers for frame decoding (sized to the current frame's crop rect)
     rgb_buffer: Option<Vec<u8>>,
     alpha_buffer: Option<Vec<u8>>,
     metadata_only: bool,
     // ICC profile for color management
     icc_profile: Option<Vec<u8>>,
+    // Whether icc_profile already matches the sRGB output space, cached alongside it
+    icc_is_srgb: bool,
+    rendering_intent: RenderingIntent,
+    // Skip embedded-ICC transforms entirely; the caller will color-manage downstream
+    discard_color_profile: bool,
+    transform_cache: TransformCache,
     // Original number of color channels (before any conversion)
     original_color_channels: usize,
+    // Full-canvas f32 RGBA buffer that frames are composited into
+    canvas: Option<Vec<f32>>,
+    current_frame: Option<FrameInfo>,
+    frame_count: u32,
+    progressive_detail: Option<ProgressiveDetail>,
+    preview_ready: bool,
+    // How many times decoding of the current frame has paused for more input
+    // while progressive decoding is enabled.
+    preview_flush_count: u32,
+    max_pixels: usize,
+    max_bytes: usize,
+    too_large: bool,
+    // Thumbnail / size-constrained decoding
+    max_output_width: Option<u32>,
+    max_output_height: Option<u32>,
+    downsample_factor: u32,
+    // Dimensions of the canvas/frame buffers in decode-native (pre-orientation)
+    // order, i.e. before `orientation` below is applied at final packing time.
+    native_width: u32,
+    native_height: u32,
+    orientation: Orientation,
+    // Whether the source's alpha channel is already premultiplied into the
+    // color channels, per `basic_info`; affects the Blend compositing formula.
+    alpha_premultiplied: bool,
+    // Independent scan of the container's Exif/xml (XMP) metadata boxes
+    metadata_boxes: MetadataBoxWalker,
 }
 
 impl JxlRustDecoder {
@@ -66,24 +487,168 @@ impl JxlRustDecoder {
             cached_info: None,
             frame_ready: false,
             decoded_pixels: None,
+            decoded_pixels_wide: None,
+            output_format: OutputFormat::Bgra8,
             rgb_buffer: None,
             alpha_buffer: None,
             metadata_only,
             icc_profile: None,
+            icc_is_srgb: true,
+            rendering_intent: RenderingIntent::Perceptual,
+            discard_color_profile: false,
+            transform_cache: TransformCache::new(),
             original_color_channels: 3,
+            canvas: None,
+            current_frame: None,
+            frame_count: 0,
+            progressive_detail: None,
+            preview_ready: false,
+            preview_flush_count: 0,
+            max_pixels: usize::MAX,
+            max_bytes: usize::MAX,
+            too_large: false,
+            max_output_width: None,
+            max_output_height: None,
+            downsample_factor: 1,
+            native_width: 0,
+            native_height: 0,
+            orientation: Orientation::Identity,
+            alpha_premultiplied: false,
+            metadata_boxes: MetadataBoxWalker::new(),
+        }
+    }
+
+    /// Request a downscaled decode sized for `max_width` x `max_height`, e.g. for
+    /// a thumbnail or a surface-cache size probe. Despite the name, this is not a
+    /// strict "at most" cap: the decoder picks the coarsest JXL downsampling
+    /// factor (1, 2, 4 or 8) whose output still covers the request, so the actual
+    /// decoded size can come out larger than `max_width` x `max_height` (smaller
+    /// only when the image itself is smaller than the request, which is then
+    /// decoded at 1:1), and callers needing an exact size must still crop/scale it down
+    /// themselves. Must be called before the first call to `process_data`.
+    pub fn set_max_output_size(&mut self, max_width: u32, max_height: u32) {
+        self.max_output_width = Some(max_width);
+        self.max_output_height = Some(max_height);
+    }
+
+    /// `ceil(numerator / denominator)`, for sizing buffers from a downsampling
+    /// factor. We don't have a way to confirm from here exactly how `jxl`
+    /// rounds its own downsampled output dimensions, so round up rather than
+    /// down: an over-sized buffer just wastes a little memory, while an
+    /// under-sized one would let the decoder write out of bounds.
+    fn ceil_div(numerator: usize, denominator: usize) -> usize {
+        (numerator + denominator - 1) / denominator
+    }
+
+    /// Pick the coarsest of the JXL downsampling factors (1:1, 1:2, 1:4, 1:8)
+    /// whose output still covers the requested max output size.
+    fn compute_downsample_factor(&self, native_width: u32, native_height: u32) -> u32 {
+        let (Some(max_width), Some(max_height)) = (self.max_output_width, self.max_output_height)
+        else {
+            return 1;
+        };
+        for factor in [8, 4, 2, 1] {
+            if native_width / factor >= max_width.max(1) && native_height / factor >= max_height.max(1)
+            {
+                return factor;
+            }
         }
+        1
+    }
+
+    /// Cap dimensions and the size of each allocated buffer (buffers are checked
+    /// one at a time, not summed), so a crafted header can't trigger
+    /// an enormous or overflowing allocation. Unset (the default) means no limit.
+    pub fn set_memory_limit(&mut self, max_pixels: usize, max_bytes: usize) {
+        self.max_pixels = max_pixels;
+        self.max_bytes = max_bytes;
+    }
+
+    /// Whether decoding stopped because the image exceeded the configured memory
+    /// limit (see `set_memory_limit`), as opposed to any other decode error.
+    pub fn exceeded_memory_limit(&self) -> bool {
+        self.too_large
+    }
+
+    /// Checked `width * height`, rejecting overflow and anything over `max_pixels`.
+    fn check_pixel_limit(&self, width: usize, height: usize) -> Result<usize, &'static str> {
+        let pixel_count = width
+            .checked_mul(height)
+            .ok_or("Image dimensions overflow")?;
+        if pixel_count > self.max_pixels {
+            return Err("Image exceeds configured pixel limit");
+        }
+        Ok(pixel_count)
+    }
+
+    /// Checked `pixel_count * bytes_per_pixel`, rejecting overflow and anything
+    /// over `max_bytes`. Returns the byte length to allocate.
+    fn checked_byte_len(
+        &self,
+        pixel_count: usize,
+        bytes_per_pixel: usize,
+    ) -> Result<usize, &'static str> {
+        let byte_len = pixel_count
+            .checked_mul(bytes_per_pixel)
+            .ok_or("Buffer size overflow")?;
+        if byte_len > self.max_bytes {
+            return Err("Image exceeds configured memory limit");
+        }
+        Ok(byte_len)
+    }
+
+    /// Request progressive (incremental) decoding, so `process_data` can report a
+    /// preview ready before the full frame has streamed in. Must be called before
+    /// the first call to `process_data`.
+    pub fn set_progressive_detail(&mut self, detail: ProgressiveDetail) {
+        self.progressive_detail = Some(detail);
+    }
+
+    /// Set the rendering intent used for embedded-ICC color transforms.
+    /// Defaults to `RenderingIntent::Perceptual`.
+    pub fn set_rendering_intent(&mut self, intent: RenderingIntent) {
+        self.rendering_intent = intent;
+    }
+
+    /// Skip embedded-ICC color transforms entirely, on the assumption the caller
+    /// will color-manage the raw output downstream.
+    pub fn set_discard_color_profile(&mut self, discard: bool) {
+        self.discard_color_profile = discard;
+    }
+
+    /// Select the pixel format `decode_frame`/`decode_frame_wide` produce.
+    /// Defaults to `OutputFormat::Bgra8`. Must be called before the first call
+    /// to `process_data`.
+    pub fn set_output_format(&mut self, format: OutputFormat) {
+        self.output_format = format;
+    }
+
+    /// The output format selected via `set_output_format`.
+    pub fn output_format(&self) -> OutputFormat {
+        self.output_format
     }
 
     /// Process JXL data and advance the decoder state.
     /// Returns (done, size_hint) where done indicates completion and
     /// size_hint suggests optimal buffer size for more data.
     pub fn process_data(&mut self, mut data: &[u8]) -> Result<(bool, usize), &'static str> {
+        // Independently walk the container's metadata boxes for Exif/XMP. This
+        // never consumes from `data`; the `jxl` crate below still sees (and
+        // decodes) the full stream itself.
+        self.metadata_boxes.feed(data);
+
         loop {
             match &mut self.state {
                 DecoderState::Uninitialized => {
                     // Create decoder with default options
                     let mut options = JxlDecoderOptions::default();
                     options.xyb_output_linear = false;
+                    if let Some(detail) = self.progressive_detail {
+                        options.progressive_detail = match detail {
+                            ProgressiveDetail::Dc => jxl::api::ProgressiveDetail::DC,
+                            ProgressiveDetail::AllPasses => jxl::api::ProgressiveDetail::AllPasses,
+                        };
+                    }
                     self.state = DecoderState::Initialized(JxlDecoder::<Initialized>::new(options));
                 }
 
@@ -101,6 +666,16 @@ impl JxlRustDecoder {
 
                             // Cache image info
                             self.cache_image_info(&decoder_with_info);
+
+                            let info = self.cached_info.as_ref().ok_or("No cached info")?;
+                            if let Err(e) =
+                                self.check_pixel_limit(info.width as usize, info.height as usize)
+                            {
+                                self.too_large = true;
+                                self.state = DecoderState::Error(e.to_string());
+                                return Err(e);
+                            }
+
                             self.state = DecoderState::WithImageInfo(decoder_with_info);
 
                             // If this is metadata-only decode, return early
@@ -128,29 +703,114 @@ impl JxlRustDecoder {
 
                 DecoderState::WithImageInfo(_) => {
                     // Take ownership of the decoder
-                    let decoder =
+                    let mut decoder =
                         match std::mem::replace(&mut self.state, DecoderState::Uninitialized) {
                             DecoderState::WithImageInfo(decoder) => decoder,
                             _ => unreachable!(),
                         };
 
+                    // Ask the frame decode to only reconstruct up to this resolution,
+                    // skipping the higher-frequency AC groups entirely for a thumbnail decode.
+                    if self.downsample_factor > 1 {
+                        decoder.set_downsampling(self.downsample_factor as u8);
+                    }
+
                     match decoder.process(&mut data) {
                         Ok(ProcessingResult::Complete { result }) => {
-                            // Frame info successfully parsed, prepare output buffers
+                            // Frame info successfully parsed, prepare output buffers.
+                            // The canvas itself stays in decode-native (pre-orientation)
+                            // order, since frame crop rects are reported in that order;
+                            // orientation is only applied when packing the final output.
                             let info = self.cached_info.as_ref().ok_or("No cached info")?;
-                            let (width, height) = if info.orientation_transpose {
-                                (info.height as usize, info.width as usize)
-                            } else {
-                                (info.width as usize, info.height as usize)
+                            let canvas_width = self.native_width as usize;
+                            let canvas_height = self.native_height as usize;
+
+                            if self.canvas.is_none() {
+                                let canvas_bytes = match self
+                                    .check_pixel_limit(canvas_width, canvas_height)
+                                    .and_then(|pixel_count| self.checked_byte_len(pixel_count, 16))
+                                {
+                                    Ok(bytes) => bytes,
+                                    Err(e) => {
+                                        self.too_large = true;
+                                        self.state = DecoderState::Error(e.to_string());
+                                        return Err(e);
+                                    }
+                                };
+                                self.canvas = Some(vec![0.0; canvas_bytes / 4]);
+                            }
+
+                            let header = result.frame_header();
+                            // Crop bounds are reported in native-resolution coordinates; scale
+                            // them down to match the (possibly downsampled) canvas.
+                            let factor = self.downsample_factor as usize;
+                            let blend_mode = BlendMode::from_frame_header(header.blend_info.mode);
+                            let blend_source = header.blend_info.source;
+                            // We only track one running canvas, which stands in for
+                            // reference-frame-buffer slot 0. A frame blending against any
+                            // other slot would need its own saved buffer we don't keep, so
+                            // reject it instead of compositing against the wrong pixels.
+                            if blend_mode != BlendMode::Replace && blend_source != 0 {
+                                let msg = format!(
+                                    "Unsupported frame blending: source slot {blend_source} \
+                                     is not the running canvas (slot 0)"
+                                );
+                                self.state = DecoderState::Error(msg);
+                                return Err("Unsupported frame blend reference");
+                            }
+                            let frame = FrameInfo {
+                                x0: header.bounds.x0 as usize / factor,
+                                y0: header.bounds.y0 as usize / factor,
+                                // Round up rather than floor: see `ceil_div`'s doc comment for why.
+                                width: Self::ceil_div(header.bounds.width as usize, factor).max(1),
+                                height: Self::ceil_div(header.bounds.height as usize, factor)
+                                    .max(1),
+                                blend_mode,
+                                blend_source,
+                                duration_ticks: header.duration,
+                                is_last: header.is_last,
                             };
+                            self.current_frame = Some(frame);
+                            self.preview_ready = false;
+                            self.preview_flush_count = 0;
 
                             // Allocate buffers based on original channel count (before any conversion)
-                            // Each channel is 4 bytes (f32)
-                            let bytes_per_pixel = self.original_color_channels * 4;
+                            // Each channel is 4 bytes (f32). Buffers are sized to this frame's crop
+                            // rect, not the full canvas, since a frame may only update part of it.
+                            let frame_pixel_count = match self
+                                .check_pixel_limit(frame.width, frame.height)
+                            {
+                                Ok(count) => count,
+                                Err(e) => {
+                                    self.too_large = true;
+                                    self.state = DecoderState::Error(e.to_string());
+                                    return Err(e);
+                                }
+                            };
+
+                            let rgb_bytes_per_pixel = self.original_color_channels * 4;
+                            let rgb_len = match self
+                                .checked_byte_len(frame_pixel_count, rgb_bytes_per_pixel)
+                            {
+                                Ok(len) => len,
+                                Err(e) => {
+                                    self.too_large = true;
+                                    self.state = DecoderState::Error(e.to_string());
+                                    return Err(e);
+                                }
+                            };
+                            self.rgb_buffer = Some(vec![0; rgb_len]);
 
-                            self.rgb_buffer = Some(vec![0; width * height * bytes_per_pixel]);
                             self.alpha_buffer = if info.has_alpha {
-                                Some(vec![0; width * height * 4])
+                                let alpha_len = match self.checked_byte_len(frame_pixel_count, 4) {
+                                    Ok(len) => len,
+                                    Err(e) => {
+                                        self.too_large = true;
+                                        self.state = DecoderState::Error(e.to_string());
+                                        return Err(e);
+                                    }
+                                };
+                                Some(vec![0; alpha_len])
                             } else {
                                 None
                             };
@@ -175,13 +835,10 @@ impl JxlRustDecoder {
                 }
 
                 DecoderState::WithFrameInfo(_) => {
-                    // Use existing persistent buffers
+                    // Use existing persistent buffers, sized to the current frame's crop rect
                     let info = self.cached_info.as_ref().ok_or("No cached info")?;
-                    let (width, height) = if info.orientation_transpose {
-                        (info.height as usize, info.width as usize)
-                    } else {
-                        (info.width as usize, info.height as usize)
-                    };
+                    let frame = self.current_frame.ok_or("No current frame info")?;
+                    let (width, height) = (frame.width, frame.height);
 
                     // Create output buffers from the persistent buffers
                     // Calculate bytes per row based on original number of color channels
@@ -205,8 +862,6 @@ impl JxlRustDecoder {
                     match decoder.process(&mut data, &mut buffers) {
                         Ok(ProcessingResult::Complete { result }) => {
                             // Frame decoded successfully - convert the pixel data
-                            let pixel_count = width * height;
-                            let mut decoded_pixels = vec![0u32; pixel_count];
                             // Get the buffer data for conversion
                             let rgb_bytes = self.rgb_buffer.as_mut().unwrap();
 
@@ -234,37 +889,114 @@ impl JxlRustDecoder {
                                     ));
                                     return Err("No ICC profile for multi-channel image");
                                 }
-                            } else if info.is_grayscale {
-                                1 // Grayscale has 1 color channel
                             } else {
-                                3 // RGB has 3 color channels
+                                let num_color_channels = if info.is_grayscale { 1 } else { 3 };
+
+                                // RGB/grayscale images with a non-sRGB embedded profile still need
+                                // color management, unless the caller said it would handle that
+                                // itself. This transform clamps to 8-bit sRGB, so it only makes
+                                // sense for Bgra8 output; for the wide formats it would crush
+                                // wide-gamut/HDR source data to SDR before it ever reaches the
+                                // 16-bit packing below, so leave those untouched and let the
+                                // compositor manage color using the reported transfer function
+                                // and primaries instead.
+                                if !self.discard_color_profile
+                                    && !self.icc_is_srgb
+                                    && self.output_format == OutputFormat::Bgra8
+                                {
+                                    if let Some(icc_data) = &self.icc_profile {
+                                        if !apply_icc_transform(
+                                            rgb_bytes,
+                                            width,
+                                            height,
+                                            icc_data,
+                                            self.rendering_intent,
+                                            num_color_channels,
+                                            &mut self.transform_cache,
+                                        ) {
+                                            self.state = DecoderState::Error(
+                                                "Failed to apply embedded ICC color transform"
+                                                    .to_string(),
+                                            );
+                                            return Err("Failed to apply color transform");
+                                        }
+                                    }
+                                }
+
+                                num_color_channels
                             };
 
-                            // Convert pixels
-                            convert_f32_rgb_to_u32_bgra(
+                            // The canvas stays in decode-native order; only the final
+                            // packed buffer below is reoriented.
+                            let canvas_width = self.native_width as usize;
+                            let canvas_height = self.native_height as usize;
+                            let canvas = self.canvas.as_mut().ok_or("No canvas allocated")?;
+                            composite_frame_onto_canvas(
+                                canvas,
+                                canvas_width,
+                                canvas_height,
                                 rgb_bytes,
-                                &mut decoded_pixels,
-                                width,
-                                height,
-                                info.has_alpha,
                                 alpha_bytes,
-                                actual_color_channels, // Use actual channels after transformation
+                                frame,
+                                actual_color_channels,
+                                self.alpha_premultiplied,
                             );
 
-                            // Store decoded pixels and clean up buffers
-                            self.decoded_pixels = Some(decoded_pixels);
+                            let oriented_pixel_count = info.width as usize * info.height as usize;
+                            match self.output_format {
+                                OutputFormat::Bgra8 => {
+                                    let mut decoded_pixels = vec![0u32; oriented_pixel_count];
+                                    convert_f32_rgba_canvas_to_u32_bgra(
+                                        canvas,
+                                        &mut decoded_pixels,
+                                        canvas_width,
+                                        canvas_height,
+                                        self.orientation,
+                                    );
+                                    self.decoded_pixels = Some(decoded_pixels);
+                                    self.decoded_pixels_wide = None;
+                                }
+                                OutputFormat::Rgba16 | OutputFormat::Rgba16Float => {
+                                    let mut decoded_pixels_wide =
+                                        vec![0u16; oriented_pixel_count * 4];
+                                    convert_f32_rgba_canvas_to_wide_rgba(
+                                        canvas,
+                                        &mut decoded_pixels_wide,
+                                        canvas_width,
+                                        canvas_height,
+                                        self.orientation,
+                                        self.output_format,
+                                    );
+                                    self.decoded_pixels_wide = Some(decoded_pixels_wide);
+                                    self.decoded_pixels = None;
+                                }
+                            }
+
+                            // Clean up the per-frame buffers
                             self.rgb_buffer = None;
                             self.alpha_buffer = None;
+                            self.frame_count += 1;
                             self.state = DecoderState::WithImageInfo(result);
                             self.frame_ready = true;
+                            // The frame is fully decoded now, not just previewable; stop
+                            // reporting a stale preview until the next frame starts one.
+                            self.preview_ready = false;
+                            self.preview_flush_count = 0;
 
-                            // Frame decode complete
+                            // Frame decode complete; C++ pulls the next frame by feeding more
+                            // data and calling advance_frame()/process_data() again.
                             return Ok((true, 0));
                         }
                         Ok(ProcessingResult::NeedsMoreInput {
                             fallback,
                             size_hint: hint,
                         }) => {
+                            // Once progressive decoding is enabled, any pass that has been
+                            // flushed into rgb_buffer is enough for flush_preview() to draw from.
+                            if self.progressive_detail.is_some() {
+                                self.preview_ready = true;
+                                self.preview_flush_count += 1;
+                            }
                             if data.is_empty() {
                                 return Ok((false, hint));
                             }
@@ -287,6 +1019,7 @@ impl JxlRustDecoder {
     fn cache_image_info(&mut self, decoder: &JxlDecoder<WithImageInfo>) {
         let basic_info = decoder.basic_info();
         let pixel_format = decoder.current_pixel_format();
+        self.alpha_premultiplied = basic_info.alpha_premultiplied;
 
         // Determine number of color channels based on color type
         // jxl-rs outputs actual channels, so we need to check what we're really getting
@@ -308,17 +1041,62 @@ impl JxlRustDecoder {
         let icc_bytes = color_profile.as_icc();
         self.icc_profile = Some(icc_bytes.to_vec());
 
+        let transfer_function =
+            TransferFunction::from_jxl(basic_info.color_encoding.transfer_function);
+        let primaries = ColorPrimaries::from_jxl(basic_info.color_encoding.primaries);
+
+        // Compare the parsed color space, not the serialized ICC bytes: jxl-rs's
+        // emitted sRGB profile is not byte-identical to qcms's own, so a raw
+        // byte comparison would misclassify ordinary sRGB images as non-sRGB and
+        // send nearly every non-CMYK frame through the 8-bit qcms round-trip below.
+        self.icc_is_srgb =
+            primaries == ColorPrimaries::Srgb && transfer_function == TransferFunction::Srgb;
+
         let is_grayscale = matches!(
             pixel_format.color_type,
             JxlColorType::Grayscale | JxlColorType::GrayscaleAlpha
         );
 
+        let (tps_numerator, tps_denominator, num_loops) = match &basic_info.animation {
+            Some(animation) => (
+                animation.tps_numerator,
+                animation.tps_denominator,
+                animation.num_loops,
+            ),
+            None => (0, 0, 0),
+        };
+
+        let native_width = basic_info.size.0 as u32;
+        let native_height = basic_info.size.1 as u32;
+        self.downsample_factor = self.compute_downsample_factor(native_width, native_height);
+        // Round up rather than floor: see `ceil_div`'s doc comment for why.
+        self.native_width =
+            (Self::ceil_div(native_width as usize, self.downsample_factor as usize) as u32).max(1);
+        self.native_height =
+            (Self::ceil_div(native_height as usize, self.downsample_factor as usize) as u32)
+                .max(1);
+        self.orientation = Orientation::from_basic_info(basic_info.orientation);
+
+        let (width, height) = if self.orientation.is_transposing() {
+            (self.native_height, self.native_width)
+        } else {
+            (self.native_width, self.native_height)
+        };
+
         let info = CachedImageInfo {
-            width: basic_info.size.0 as u32,
-            height: basic_info.size.1 as u32,
+            width,
+            height,
             has_alpha,
-            orientation_transpose: basic_info.orientation.is_transposing(),
             is_grayscale,
+            tps_numerator,
+            tps_denominator,
+            num_loops,
+            transfer_function,
+            primaries,
+            // Bgra8 output is clamped to 0..255 when packed, so it never
+            // actually carries HDR range, whatever the source transfer
+            // function claims.
+            is_hdr: transfer_function.is_hdr() && self.output_format != OutputFormat::Bgra8,
         };
 
         self.cached_info = Some(info);
@@ -328,6 +1106,106 @@ impl JxlRustDecoder {
         self.frame_ready
     }
 
+    /// Number of frames decoded so far. For a still image this is 0 or 1; for an
+    /// animation it grows as `process_data`/`advance_frame` walk the frame sequence.
+    /// The final count is only known once the last frame has been decoded.
+    pub fn frame_count(&self) -> u32 {
+        self.frame_count
+    }
+
+    /// Duration of the most recently decoded frame, in ticks; one tick lasts
+    /// `tps_denominator / tps_numerator` seconds (see `CachedImageInfo`).
+    pub fn current_frame_duration_ticks(&self) -> Option<u32> {
+        self.current_frame.map(|frame| frame.duration_ticks)
+    }
+
+    /// Whether the most recently decoded frame is the last one in the animation.
+    pub fn is_last_frame(&self) -> bool {
+        self.current_frame.map(|frame| frame.is_last).unwrap_or(true)
+    }
+
+    /// Mark the current frame as consumed so that feeding more data resumes decoding
+    /// the next frame of an animation, without requiring the caller to extract pixels
+    /// via `decode_frame` first. Returns whether another frame is expected to follow.
+    pub fn advance_frame(&mut self) -> Result<bool, &'static str> {
+        if !self.frame_ready {
+            return Err("Frame not ready to advance");
+        }
+        let is_last = self.is_last_frame();
+        self.frame_ready = false;
+        self.decoded_pixels = None;
+        self.decoded_pixels_wide = None;
+        Ok(!is_last)
+    }
+
+    /// Whether a progressive preview of the frame currently being decoded is
+    /// available. Only ever true when `set_progressive_detail` was called.
+    pub fn is_preview_ready(&self) -> bool {
+        self.preview_ready
+    }
+
+    /// How many times decoding of the current frame has paused for more input
+    /// while progressive decoding is enabled. Pass boundaries are not
+    /// inspected, so this counts pauses, not completed passes. Resets to 0 at
+    /// the start of each frame.
+    pub fn flushed_pass_count(&self) -> u32 {
+        self.preview_flush_count
+    }
+
+    /// Write the best current approximation of the in-progress frame into `output`,
+    /// at full canvas resolution. Only valid while `is_preview_ready()` is true; does
+    /// not consume the frame, so decoding can keep resuming against the same buffers.
+    pub fn flush_preview(&self, output: &mut [u32]) -> Result<usize, &'static str> {
+        if !self.preview_ready {
+            return Err("Preview not ready");
+        }
+
+        let info = self.cached_info.as_ref().ok_or("No cached info")?;
+        let frame = self.current_frame.ok_or("No current frame info")?;
+        let rgb_bytes = self.rgb_buffer.as_deref().ok_or("No RGB buffer allocated")?;
+        let alpha_bytes = self.alpha_buffer.as_deref();
+
+        // The canvas stays in decode-native order; the pack step below reorients it.
+        let canvas_width = self.native_width as usize;
+        let canvas_height = self.native_height as usize;
+        let pixel_count = canvas_width * canvas_height;
+        if output.len() < pixel_count {
+            return Err("Output buffer too small");
+        }
+
+        if self.original_color_channels > 3 {
+            // The CMYK->RGB ICC transform only runs once the frame is fully decoded,
+            // so there is no sRGB-comparable preview to show yet.
+            return Err("Preview not available for untransformed CMYK data");
+        }
+        let preview_color_channels = if info.is_grayscale { 1 } else { 3 };
+        let mut canvas = self
+            .canvas
+            .clone()
+            .unwrap_or_else(|| vec![0.0; pixel_count * 4]);
+        composite_frame_onto_canvas(
+            &mut canvas,
+            canvas_width,
+            canvas_height,
+            rgb_bytes,
+            alpha_bytes,
+            frame,
+            preview_color_channels,
+            self.alpha_premultiplied,
+        );
+
+        let mut pixels = vec![0u32; pixel_count];
+        convert_f32_rgba_canvas_to_u32_bgra(
+            &canvas,
+            &mut pixels,
+            canvas_width,
+            canvas_height,
+            self.orientation,
+        );
+        output[..pixel_count].copy_from_slice(&pixels);
+        Ok(pixel_count)
+    }
+
     /// Extract decoded pixels into the provided output buffer.
     ///
     /// The frame must be ready (check with is_frame_ready()) before calling this function.
@@ -355,6 +1233,118 @@ impl JxlRustDecoder {
             Err("No decoded pixels available")
         }
     }
+
+    /// Extract decoded pixels into the provided output buffer as 16-bit or
+    /// half-float RGBA (four `u16` per pixel), per `set_output_format`. Only
+    /// valid when the output format is `Rgba16` or `Rgba16Float`.
+    ///
+    /// The frame must be ready (check with is_frame_ready()) before calling this function.
+    /// After successful extraction, the decoder is reset for the next frame.
+    pub fn decode_frame_wide(&mut self, output: &mut [u16]) -> Result<usize, &'static str> {
+        if !self.frame_ready {
+            return Err("Frame not ready for decoding");
+        }
+
+        if let Some(pixels) = &self.decoded_pixels_wide {
+            let len = pixels.len();
+
+            if output.len() < len {
+                return Err("Output buffer too small");
+            }
+
+            output[..len].copy_from_slice(pixels);
+
+            // Reset for next frame
+            self.frame_ready = false;
+            self.decoded_pixels_wide = None;
+
+            Ok(len / 4)
+        } else {
+            Err("No decoded pixels available")
+        }
+    }
+
+    /// Raw embedded EXIF payload, if a container `Exif` box has streamed in so
+    /// far. The box's leading 4 bytes are a big-endian TIFF-header offset
+    /// (usually 0), already skipped along with the offset itself, so this
+    /// starts directly at the TIFF header.
+    pub fn exif_data(&self) -> Option<&[u8]> {
+        let exif = self.metadata_boxes.exif.as_deref()?;
+        let offset = u32::from_be_bytes(exif.get(0..4)?.try_into().ok()?) as usize;
+        exif.get(4 + offset..)
+    }
+
+    /// Raw embedded XMP payload (UTF-8 XML), if a container `xml ` box has
+    /// streamed in so far.
+    pub fn xmp_data(&self) -> Option<&[u8]> {
+        self.metadata_boxes.xmp.as_deref()
+    }
+}
+
+/// Apply an embedded ICC profile's transform to the output space in place, for
+/// RGB (3-channel) f32 data. Grayscale (1-channel) data is left untouched, as
+/// qcms has no usable gray->gray sRGB transform here. Reuses a compiled
+/// `Transform` from `cache` when the same profile/intent/channel count was
+/// seen before.
+fn apply_icc_transform(
+    data: &mut [u8],
+    width: usize,
+    height: usize,
+    icc_data: &[u8],
+    intent: RenderingIntent,
+    num_color_channels: usize,
+    cache: &mut TransformCache,
+) -> bool {
+    if num_color_channels == 1 {
+        // qcms's sRGB output profile is an RGB profile; handing it to a
+        // Gray8->Gray8 transform leaves input and output color spaces
+        // mismatched and the transform reliably fails to build. Grayscale
+        // embedded profiles are rare enough that matching baseline (which
+        // ignored them entirely) is preferable to hard-failing the decode.
+        return true;
+    }
+    let data_type = DataType::RGB8;
+
+    let key = transform_cache_key(icc_data, intent, num_color_channels);
+    if cache.get(key).is_none() {
+        let input_profile = match Profile::new_from_slice(icc_data, false) {
+            Some(p) => p,
+            None => return false,
+        };
+        let output_profile = Profile::new_sRGB();
+        let transform = match Transform::new_to(
+            &input_profile,
+            &output_profile,
+            data_type,
+            data_type,
+            intent.to_qcms(),
+        ) {
+            Some(t) => t,
+            None => return false,
+        };
+        cache.insert(key, transform);
+    }
+    let transform = cache.get(key).expect("just inserted into the cache");
+
+    // Unlike the CMYK path, RGB/grayscale buffers already carry 0..255-scale
+    // floats (see convert_f32_rgba_canvas_to_u32_bgra), so no /255 normalization.
+    let pixel_count = width * height;
+    let sample_count = pixel_count * num_color_channels;
+    let mut in_u8 = vec![0u8; sample_count];
+    let mut out_u8 = vec![0u8; sample_count];
+
+    for i in 0..sample_count {
+        let f32_val = f32::from_ne_bytes(data[i * 4..(i + 1) * 4].try_into().unwrap());
+        in_u8[i] = f32_val.clamp(0.0, 255.0) as u8;
+    }
+
+    transform.convert(&in_u8, &mut out_u8);
+
+    for i in 0..sample_count {
+        let f32_val = out_u8[i] as f32;
+        data[i * 4..(i + 1) * 4].copy_from_slice(&f32_val.to_ne_bytes());
+    }
+    true
 }
 
 /// Apply color transform from CMYK to RGB
@@ -407,72 +1397,221 @@ fn apply_cmyk_to_rgb_transform(
     true
 }
 
-/// Convert f32 RGB/Grayscale/CMYK to u32 BGRA packed format
-fn convert_f32_rgb_to_u32_bgra(
-    rgb_buffer: &[u8],
-    output: &mut [u32],
-    width: usize,
-    height: usize,
-    has_alpha: bool,
-    alpha_buffer: Option<&[u8]>,
+/// Read the (r, g, b, a) values (0..255 scale) of one pixel out of a frame's
+/// decoded color/alpha buffers, replicating grayscale to RGB as needed.
+fn read_frame_pixel(
+    rgb_bytes: &[u8],
+    alpha_bytes: Option<&[u8]>,
+    pixel_idx: usize,
+    num_color_channels: usize,
+) -> (f32, f32, f32, f32) {
+    let (r, g, b) = if num_color_channels == 1 {
+        let offset = pixel_idx * 4;
+        let gray = f32::from_ne_bytes(rgb_bytes[offset..offset + 4].try_into().unwrap());
+        (gray, gray, gray)
+    } else {
+        let offset = pixel_idx * 12;
+        let r = f32::from_ne_bytes(rgb_bytes[offset..offset + 4].try_into().unwrap());
+        let g = f32::from_ne_bytes(rgb_bytes[offset + 4..offset + 8].try_into().unwrap());
+        let b = f32::from_ne_bytes(rgb_bytes[offset + 8..offset + 12].try_into().unwrap());
+        (r, g, b)
+    };
+    let a = match alpha_bytes {
+        Some(alpha) => {
+            let offset = pixel_idx * 4;
+            f32::from_ne_bytes(alpha[offset..offset + 4].try_into().unwrap())
+        }
+        None => 255.0,
+    };
+    (r, g, b, a)
+}
+
+/// Composite a decoded frame's crop rect onto the persistent canvas according to
+/// its blend mode. `canvas` is a flat f32 RGBA buffer covering the whole image.
+fn composite_frame_onto_canvas(
+    canvas: &mut [f32],
+    canvas_width: usize,
+    canvas_height: usize,
+    rgb_bytes: &[u8],
+    alpha_bytes: Option<&[u8]>,
+    frame: FrameInfo,
     num_color_channels: usize,
+    alpha_premultiplied: bool,
 ) {
-    for y in 0..height {
-        for x in 0..width {
-            let pixel_idx = y * width + x;
-
-            // Extract f32 values based on number of color channels
-            let (r, g, b) = if num_color_channels == 1 {
-                // Grayscale: single channel, replicate to RGB
-                let gray_offset = pixel_idx * 4;
-                let gray = f32::from_ne_bytes(
-                    rgb_buffer[gray_offset..gray_offset + 4].try_into().unwrap(),
-                );
-                (gray, gray, gray)
-            } else if num_color_channels == 3 {
-                // RGB: 3 channels (includes converted CMYK)
-                let rgb_offset = pixel_idx * 12;
-                let r =
-                    f32::from_ne_bytes(rgb_buffer[rgb_offset..rgb_offset + 4].try_into().unwrap());
-                let g = f32::from_ne_bytes(
-                    rgb_buffer[rgb_offset + 4..rgb_offset + 8]
-                        .try_into()
-                        .unwrap(),
-                );
-                let b = f32::from_ne_bytes(
-                    rgb_buffer[rgb_offset + 8..rgb_offset + 12]
-                        .try_into()
-                        .unwrap(),
-                );
-                (r, g, b)
-            } else {
-                // Shouldn't reach here after conversion
-                (0.0, 0.0, 0.0)
-            };
+    for fy in 0..frame.height {
+        let y = frame.y0 + fy;
+        if y >= canvas_height {
+            continue;
+        }
+        for fx in 0..frame.width {
+            let x = frame.x0 + fx;
+            if x >= canvas_width {
+                continue;
+            }
+
+            let src_idx = fy * frame.width + fx;
+            let (src_r, src_g, src_b, src_a) =
+                read_frame_pixel(rgb_bytes, alpha_bytes, src_idx, num_color_channels);
+
+            let dst_idx = (y * canvas_width + x) * 4;
+            let (dst_r, dst_g, dst_b, dst_a) = (
+                canvas[dst_idx],
+                canvas[dst_idx + 1],
+                canvas[dst_idx + 2],
+                canvas[dst_idx + 3],
+            );
 
-            // Get alpha if available
-            let a = if has_alpha {
-                if let Some(alpha) = alpha_buffer {
-                    let alpha_offset = pixel_idx * 4;
-                    f32::from_ne_bytes(alpha[alpha_offset..alpha_offset + 4].try_into().unwrap())
-                } else {
-                    255.0
+            let (out_r, out_g, out_b, out_a) = match frame.blend_mode {
+                BlendMode::Replace => (src_r, src_g, src_b, src_a),
+                BlendMode::Add => (
+                    dst_r + src_r,
+                    dst_g + src_g,
+                    dst_b + src_b,
+                    dst_a + src_a,
+                ),
+                BlendMode::Blend => {
+                    let src_a_frac = src_a / 255.0;
+                    let dst_weight = 1.0 - src_a_frac;
+                    // The `src + dst*(1-src_a)` formula is only correct for an
+                    // already-premultiplied source; decoded samples are
+                    // straight alpha unless `basic_info` says otherwise, so
+                    // premultiply here before applying it.
+                    let (src_r, src_g, src_b) = if alpha_premultiplied {
+                        (src_r, src_g, src_b)
+                    } else {
+                        (src_r * src_a_frac, src_g * src_a_frac, src_b * src_a_frac)
+                    };
+                    (
+                        src_r + dst_r * dst_weight,
+                        src_g + dst_g * dst_weight,
+                        src_b + dst_b * dst_weight,
+                        src_a + dst_a * dst_weight,
+                    )
+                }
+                BlendMode::MulAdd => {
+                    let src_a_frac = src_a / 255.0;
+                    (
+                        dst_r + src_r * src_a_frac,
+                        dst_g + src_g * src_a_frac,
+                        dst_b + src_b * src_a_frac,
+                        dst_a + src_a * src_a_frac,
+                    )
                 }
-            } else {
-                255.0
             };
 
-            // Convert to u8 and pack as BGRA (actually ARGB in memory on little-endian)
-            let r_u8 = (r.clamp(0.0, 255.0)) as u8;
-            let g_u8 = (g.clamp(0.0, 255.0)) as u8;
-            let b_u8 = (b.clamp(0.0, 255.0)) as u8;
-            let a_u8 = (a.clamp(0.0, 255.0)) as u8;
+            canvas[dst_idx] = out_r;
+            canvas[dst_idx + 1] = out_g;
+            canvas[dst_idx + 2] = out_b;
+            canvas[dst_idx + 3] = out_a;
+        }
+    }
+}
+
+/// Pack the full f32 RGBA canvas into u32 BGRA, clamping each channel to 0..255.
+/// `width`/`height` describe `canvas` in decode-native order; `orientation`
+/// remaps each source pixel to its upright position in `output`, which must be
+/// sized for the (possibly width/height-swapped) oriented output.
+fn convert_f32_rgba_canvas_to_u32_bgra(
+    canvas: &[f32],
+    output: &mut [u32],
+    width: usize,
+    height: usize,
+    orientation: Orientation,
+) {
+    let dest_width = if orientation.is_transposing() {
+        height
+    } else {
+        width
+    };
+    for y in 0..height {
+        for x in 0..width {
+            let offset = (y * width + x) * 4;
+            let r_u8 = canvas[offset].clamp(0.0, 255.0) as u8;
+            let g_u8 = canvas[offset + 1].clamp(0.0, 255.0) as u8;
+            let b_u8 = canvas[offset + 2].clamp(0.0, 255.0) as u8;
+            let a_u8 = canvas[offset + 3].clamp(0.0, 255.0) as u8;
 
-            // Pack as 0xAARRGGBB for OS_RGBX format
-            output[pixel_idx] = ((a_u8 as u32) << 24)
+            let packed = ((a_u8 as u32) << 24)
                 | ((r_u8 as u32) << 16)
                 | ((g_u8 as u32) << 8)
                 | (b_u8 as u32);
+
+            let (dx, dy) = orientation.map(width, height, x, y);
+            output[dy * dest_width + dx] = packed;
+        }
+    }
+}
+
+/// Pack the full f32 RGBA canvas into 16-bit or half-float RGBA, per `format`.
+/// Unlike the 8-bit path this doesn't clamp to display range, so HDR values
+/// above reference white survive in the `Rgba16Float` case; `width`/`height`
+/// and `orientation` behave as in `convert_f32_rgba_canvas_to_u32_bgra`.
+fn convert_f32_rgba_canvas_to_wide_rgba(
+    canvas: &[f32],
+    output: &mut [u16],
+    width: usize,
+    height: usize,
+    orientation: Orientation,
+    format: OutputFormat,
+) {
+    let dest_width = if orientation.is_transposing() {
+        height
+    } else {
+        width
+    };
+    for y in 0..height {
+        for x in 0..width {
+            let offset = (y * width + x) * 4;
+            // The canvas is on a 0..255 scale; normalize to 0..1 before re-encoding.
+            let r = canvas[offset] / 255.0;
+            let g = canvas[offset + 1] / 255.0;
+            let b = canvas[offset + 2] / 255.0;
+            let a = canvas[offset + 3] / 255.0;
+
+            let (dx, dy) = orientation.map(width, height, x, y);
+            let dest_offset = (dy * dest_width + dx) * 4;
+            match format {
+                OutputFormat::Rgba16 => {
+                    output[dest_offset] = encode_u16_channel(r);
+                    output[dest_offset + 1] = encode_u16_channel(g);
+                    output[dest_offset + 2] = encode_u16_channel(b);
+                    output[dest_offset + 3] = encode_u16_channel(a);
+                }
+                OutputFormat::Rgba16Float => {
+                    output[dest_offset] = f32_to_f16_bits(r);
+                    output[dest_offset + 1] = f32_to_f16_bits(g);
+                    output[dest_offset + 2] = f32_to_f16_bits(b);
+                    output[dest_offset + 3] = f32_to_f16_bits(a);
+                }
+                OutputFormat::Bgra8 => unreachable!("caller only invokes this for wide formats"),
+            }
         }
     }
 }
+
+/// Scale a normalized (0..1; HDR may exceed 1.0) channel value into the full
+/// `u16` range, saturating since `Rgba16` has no way to represent values
+/// above 1.0.
+fn encode_u16_channel(value: f32) -> u16 {
+    (value.clamp(0.0, 1.0) * 65535.0).round() as u16
+}
+
+/// Convert an `f32` to an IEEE 754 binary16 bit pattern. Implemented by hand
+/// (rather than pulling in a dependency just for this) so subnormals flush to
+/// zero and out-of-range values saturate to infinity; acceptable for
+/// display-oriented HDR output.
+fn f32_to_f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x007f_ffff;
+
+    if exp <= 0 {
+        sign
+    } else if exp >= 0x1f {
+        sign | 0x7c00
+    } else {
+        sign | ((exp as u16) << 10) | ((mantissa >> 13) as u16)
+    }
+}
+
@@ -2,7 +2,10 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use jxl_rust_decoder::JxlRustDecoder;
+use jxl_rust_decoder::{
+    ColorPrimaries, JxlRustDecoder, OutputFormat, ProgressiveDetail, RenderingIntent,
+    TransferFunction,
+};
 
 #[repr(C)]
 pub enum JxlRustStatus {
@@ -10,12 +13,55 @@ pub enum JxlRustStatus {
     NeedMoreData,
     InvalidData,
     Error,
+    /// The image's dimensions or total allocation size exceeded the limit set
+    /// via `jxl_rust_decoder_set_memory_limit`.
+    TooLarge,
+}
+
+#[repr(C)]
+pub enum JxlRustRenderingIntent {
+    Perceptual,
+    Relative,
+    Saturation,
+    Absolute,
+}
+
+#[repr(C)]
+pub enum JxlRustOutputFormat {
+    Bgra8,
+    Rgba16,
+    Rgba16Float,
+}
+
+#[repr(C)]
+pub enum JxlRustTransferFunction {
+    Srgb,
+    Linear,
+    Gamma,
+    Pq,
+    Hlg,
+    Unknown,
+}
+
+#[repr(C)]
+pub enum JxlRustColorPrimaries {
+    Srgb,
+    Rec2020,
+    P3,
+    Custom,
 }
 
 #[repr(C)]
 pub struct JxlRustImageInfo {
     pub width: u32,
     pub height: u32,
+    // Animation metadata; tps_numerator/tps_denominator are 0 for still images
+    pub tps_numerator: u32,
+    pub tps_denominator: u32,
+    pub num_loops: u32,
+    pub transfer_function: JxlRustTransferFunction,
+    pub primaries: JxlRustColorPrimaries,
+    pub is_hdr: bool,
 }
 
 /// Create a new JXL decoder instance.
@@ -68,10 +114,57 @@ pub unsafe extern "C" fn jxl_rust_decoder_process_data(
             }
             JxlRustStatus::NeedMoreData
         }
+        Err(_) if decoder.exceeded_memory_limit() => JxlRustStatus::TooLarge,
         Err(_) => JxlRustStatus::InvalidData,
     }
 }
 
+/// Request a downscaled decode sized for `max_width` x `max_height`, e.g. for a
+/// thumbnail or a surface-cache size probe. The coarsest downsampling factor
+/// (1, 2, 4 or 8) whose output still covers the request is used, so the
+/// decoded size can be larger than requested. `jxl_rust_decoder_get_info` then
+/// reports the chosen (downsampled) dimensions instead of the native ones.
+/// Must be called before the first call to `jxl_rust_decoder_process_data`.
+///
+/// # Safety
+/// The decoder pointer must be valid and created by `jxl_rust_decoder_new`.
+#[no_mangle]
+pub unsafe extern "C" fn jxl_rust_decoder_set_max_output_size(
+    decoder: *mut JxlRustDecoder,
+    max_width: u32,
+    max_height: u32,
+) -> JxlRustStatus {
+    if decoder.is_null() {
+        return JxlRustStatus::Error;
+    }
+
+    let decoder = &mut *decoder;
+    decoder.set_max_output_size(max_width, max_height);
+    JxlRustStatus::Ok
+}
+
+/// Set a ceiling on decoded image size: `max_pixels` bounds `width * height`,
+/// `max_bytes` bounds the total size of any single allocated buffer. Pass
+/// `usize::MAX` for either to leave it unbounded. Must be called before the
+/// first call to `jxl_rust_decoder_process_data`.
+///
+/// # Safety
+/// The decoder pointer must be valid and created by `jxl_rust_decoder_new`.
+#[no_mangle]
+pub unsafe extern "C" fn jxl_rust_decoder_set_memory_limit(
+    decoder: *mut JxlRustDecoder,
+    max_pixels: usize,
+    max_bytes: usize,
+) -> JxlRustStatus {
+    if decoder.is_null() {
+        return JxlRustStatus::Error;
+    }
+
+    let decoder = &mut *decoder;
+    decoder.set_memory_limit(max_pixels, max_bytes);
+    JxlRustStatus::Ok
+}
+
 /// Get image information from the decoder.
 ///
 /// # Safety
@@ -91,6 +184,24 @@ pub unsafe extern "C" fn jxl_rust_decoder_get_info(
     if let Some(cached_info) = &decoder.cached_info {
         (*info).width = cached_info.width;
         (*info).height = cached_info.height;
+        (*info).tps_numerator = cached_info.tps_numerator;
+        (*info).tps_denominator = cached_info.tps_denominator;
+        (*info).num_loops = cached_info.num_loops;
+        (*info).transfer_function = match cached_info.transfer_function {
+            TransferFunction::Srgb => JxlRustTransferFunction::Srgb,
+            TransferFunction::Linear => JxlRustTransferFunction::Linear,
+            TransferFunction::Gamma => JxlRustTransferFunction::Gamma,
+            TransferFunction::Pq => JxlRustTransferFunction::Pq,
+            TransferFunction::Hlg => JxlRustTransferFunction::Hlg,
+            TransferFunction::Unknown => JxlRustTransferFunction::Unknown,
+        };
+        (*info).primaries = match cached_info.primaries {
+            ColorPrimaries::Srgb => JxlRustColorPrimaries::Srgb,
+            ColorPrimaries::Rec2020 => JxlRustColorPrimaries::Rec2020,
+            ColorPrimaries::P3 => JxlRustColorPrimaries::P3,
+            ColorPrimaries::Custom => JxlRustColorPrimaries::Custom,
+        };
+        (*info).is_hdr = cached_info.is_hdr;
     } else {
         return JxlRustStatus::Error;
     }
@@ -140,3 +251,310 @@ pub unsafe extern "C" fn jxl_rust_decoder_decode_frame(
         Err(_) => JxlRustStatus::Error,
     }
 }
+
+/// Select the pixel format `jxl_rust_decoder_decode_frame`/
+/// `jxl_rust_decoder_decode_frame_wide` produce. Defaults to `Bgra8`. Must be
+/// called before the first call to `jxl_rust_decoder_process_data`.
+///
+/// # Safety
+/// The decoder pointer must be valid and created by `jxl_rust_decoder_new`.
+#[no_mangle]
+pub unsafe extern "C" fn jxl_rust_decoder_set_output_format(
+    decoder: *mut JxlRustDecoder,
+    format: JxlRustOutputFormat,
+) -> JxlRustStatus {
+    if decoder.is_null() {
+        return JxlRustStatus::Error;
+    }
+
+    let decoder = &mut *decoder;
+    decoder.set_output_format(match format {
+        JxlRustOutputFormat::Bgra8 => OutputFormat::Bgra8,
+        JxlRustOutputFormat::Rgba16 => OutputFormat::Rgba16,
+        JxlRustOutputFormat::Rgba16Float => OutputFormat::Rgba16Float,
+    });
+    JxlRustStatus::Ok
+}
+
+/// Decode a frame from the JXL data as 16-bit or half-float RGBA (four `u16`
+/// per pixel), per `jxl_rust_decoder_set_output_format`. Only valid when the
+/// output format is `Rgba16` or `Rgba16Float`.
+///
+/// # Safety
+/// - The decoder pointer must be valid and created by `jxl_rust_decoder_new`.
+/// - The output_data pointer must be valid for `output_len` u16 values.
+/// - The pixels_written pointer must be valid and point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn jxl_rust_decoder_decode_frame_wide(
+    decoder: *mut JxlRustDecoder,
+    output_data: *mut u16,
+    output_len: usize,
+    pixels_written: *mut usize,
+) -> JxlRustStatus {
+    if decoder.is_null() || output_data.is_null() || pixels_written.is_null() {
+        return JxlRustStatus::Error;
+    }
+
+    let decoder = &mut *decoder;
+    let output_slice = std::slice::from_raw_parts_mut(output_data, output_len);
+
+    match decoder.decode_frame_wide(output_slice) {
+        Ok(count) => {
+            *pixels_written = count;
+            JxlRustStatus::Ok
+        }
+        Err(_) => JxlRustStatus::Error,
+    }
+}
+
+/// Set the rendering intent used for embedded-ICC color transforms.
+///
+/// # Safety
+/// The decoder pointer must be valid and created by `jxl_rust_decoder_new`.
+#[no_mangle]
+pub unsafe extern "C" fn jxl_rust_decoder_set_rendering_intent(
+    decoder: *mut JxlRustDecoder,
+    intent: JxlRustRenderingIntent,
+) -> JxlRustStatus {
+    if decoder.is_null() {
+        return JxlRustStatus::Error;
+    }
+
+    let decoder = &mut *decoder;
+    decoder.set_rendering_intent(match intent {
+        JxlRustRenderingIntent::Perceptual => RenderingIntent::Perceptual,
+        JxlRustRenderingIntent::Relative => RenderingIntent::Relative,
+        JxlRustRenderingIntent::Saturation => RenderingIntent::Saturation,
+        JxlRustRenderingIntent::Absolute => RenderingIntent::Absolute,
+    });
+    JxlRustStatus::Ok
+}
+
+/// Skip embedded-ICC color transforms entirely, on the assumption the caller
+/// will color-manage the raw output downstream.
+///
+/// # Safety
+/// The decoder pointer must be valid and created by `jxl_rust_decoder_new`.
+#[no_mangle]
+pub unsafe extern "C" fn jxl_rust_decoder_set_discard_color_profile(
+    decoder: *mut JxlRustDecoder,
+    discard: bool,
+) -> JxlRustStatus {
+    if decoder.is_null() {
+        return JxlRustStatus::Error;
+    }
+
+    let decoder = &mut *decoder;
+    decoder.set_discard_color_profile(discard);
+    JxlRustStatus::Ok
+}
+
+/// Request progressive (DC-preview or pass-by-pass) decoding. Must be called
+/// before the first call to `jxl_rust_decoder_process_data`.
+///
+/// `dc_only`: when true, `jxl_rust_decoder_flush_preview` only ever reflects the
+/// low-frequency DC pass; when false, it reflects every pass as it streams in.
+///
+/// # Safety
+/// The decoder pointer must be valid and created by `jxl_rust_decoder_new`.
+#[no_mangle]
+pub unsafe extern "C" fn jxl_rust_decoder_set_progressive_detail(
+    decoder: *mut JxlRustDecoder,
+    dc_only: bool,
+) -> JxlRustStatus {
+    if decoder.is_null() {
+        return JxlRustStatus::Error;
+    }
+
+    let decoder = &mut *decoder;
+    let detail = if dc_only {
+        ProgressiveDetail::Dc
+    } else {
+        ProgressiveDetail::AllPasses
+    };
+    decoder.set_progressive_detail(detail);
+    JxlRustStatus::Ok
+}
+
+/// Check whether a progressive preview of the in-progress frame is available.
+///
+/// # Safety
+/// The decoder pointer must be valid and created by `jxl_rust_decoder_new`.
+#[no_mangle]
+pub unsafe extern "C" fn jxl_rust_decoder_is_preview_ready(decoder: *const JxlRustDecoder) -> bool {
+    if decoder.is_null() {
+        return false;
+    }
+
+    let decoder = &*decoder;
+    decoder.is_preview_ready()
+}
+
+/// How many times decoding of the current frame has paused for more input
+/// while progressive decoding is enabled (a count of pauses, not of completed
+/// passes). Resets to 0 at the start of each frame.
+///
+/// # Safety
+/// The decoder pointer must be valid and created by `jxl_rust_decoder_new`.
+#[no_mangle]
+pub unsafe extern "C" fn jxl_rust_decoder_flushed_pass_count(
+    decoder: *const JxlRustDecoder,
+) -> u32 {
+    if decoder.is_null() {
+        return 0;
+    }
+
+    let decoder = &*decoder;
+    decoder.flushed_pass_count()
+}
+
+/// Write the current best approximation of the in-progress frame into
+/// `output_data`, at full canvas resolution. Does not consume the frame.
+///
+/// # Safety
+/// - The decoder pointer must be valid and created by `jxl_rust_decoder_new`.
+/// - The output_data pointer must be valid for `output_len` u32 values.
+/// - The pixels_written pointer must be valid and point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn jxl_rust_decoder_flush_preview(
+    decoder: *const JxlRustDecoder,
+    output_data: *mut u32,
+    output_len: usize,
+    pixels_written: *mut usize,
+) -> JxlRustStatus {
+    if decoder.is_null() || output_data.is_null() || pixels_written.is_null() {
+        return JxlRustStatus::Error;
+    }
+
+    let decoder = &*decoder;
+    let output_slice = std::slice::from_raw_parts_mut(output_data, output_len);
+
+    match decoder.flush_preview(output_slice) {
+        Ok(count) => {
+            *pixels_written = count;
+            JxlRustStatus::Ok
+        }
+        Err(_) => JxlRustStatus::Error,
+    }
+}
+
+/// Get the number of frames decoded so far. For an animation this grows as more
+/// frames are decoded; the final value is only known once the last frame completes.
+///
+/// # Safety
+/// The decoder pointer must be valid and created by `jxl_rust_decoder_new`.
+#[no_mangle]
+pub unsafe extern "C" fn jxl_rust_decoder_get_frame_count(decoder: *const JxlRustDecoder) -> u32 {
+    if decoder.is_null() {
+        return 0;
+    }
+
+    let decoder = &*decoder;
+    decoder.frame_count()
+}
+
+/// Get the duration of the most recently decoded frame, in ticks; one tick
+/// lasts `tps_denominator / tps_numerator` seconds (see `jxl_rust_decoder_get_info`).
+///
+/// # Safety
+/// The decoder pointer must be valid and created by `jxl_rust_decoder_new`.
+#[no_mangle]
+pub unsafe extern "C" fn jxl_rust_decoder_get_frame_duration(
+    decoder: *const JxlRustDecoder,
+    duration_ticks_out: *mut u32,
+) -> JxlRustStatus {
+    if decoder.is_null() || duration_ticks_out.is_null() {
+        return JxlRustStatus::Error;
+    }
+
+    let decoder = &*decoder;
+    match decoder.current_frame_duration_ticks() {
+        Some(duration_ticks) => {
+            *duration_ticks_out = duration_ticks;
+            JxlRustStatus::Ok
+        }
+        None => JxlRustStatus::Error,
+    }
+}
+
+/// Advance past the current frame so that feeding more data resumes decoding the
+/// next frame of an animation, without requiring a prior call to `decode_frame`.
+///
+/// # Safety
+/// The decoder pointer must be valid and created by `jxl_rust_decoder_new`.
+#[no_mangle]
+pub unsafe extern "C" fn jxl_rust_decoder_advance_frame(
+    decoder: *mut JxlRustDecoder,
+    has_more_frames_out: *mut bool,
+) -> JxlRustStatus {
+    if decoder.is_null() || has_more_frames_out.is_null() {
+        return JxlRustStatus::Error;
+    }
+
+    let decoder = &mut *decoder;
+    match decoder.advance_frame() {
+        Ok(has_more_frames) => {
+            *has_more_frames_out = has_more_frames;
+            JxlRustStatus::Ok
+        }
+        Err(_) => JxlRustStatus::Error,
+    }
+}
+
+/// Get the raw embedded EXIF payload, if any `Exif` container box has
+/// streamed in so far. The JXL-specific 4-byte TIFF-header-offset prefix is
+/// already skipped, so the returned bytes start directly at the TIFF header
+/// and can be handed to a standard EXIF parser. The returned pointer is owned
+/// by the decoder and is only valid until the next call into it.
+///
+/// # Safety
+/// - The decoder pointer must be valid and created by `jxl_rust_decoder_new`.
+/// - `data_out`/`len_out` must be valid and point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn jxl_rust_decoder_get_exif(
+    decoder: *const JxlRustDecoder,
+    data_out: *mut *const u8,
+    len_out: *mut usize,
+) -> JxlRustStatus {
+    if decoder.is_null() || data_out.is_null() || len_out.is_null() {
+        return JxlRustStatus::Error;
+    }
+
+    let decoder = &*decoder;
+    match decoder.exif_data() {
+        Some(exif) => {
+            *data_out = exif.as_ptr();
+            *len_out = exif.len();
+            JxlRustStatus::Ok
+        }
+        None => JxlRustStatus::Error,
+    }
+}
+
+/// Get the raw embedded XMP payload (UTF-8 XML), if any `xml ` container box
+/// has streamed in so far. The returned pointer is owned by the decoder and
+/// is only valid until the next call into it.
+///
+/// # Safety
+/// - The decoder pointer must be valid and created by `jxl_rust_decoder_new`.
+/// - `data_out`/`len_out` must be valid and point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn jxl_rust_decoder_get_xmp(
+    decoder: *const JxlRustDecoder,
+    data_out: *mut *const u8,
+    len_out: *mut usize,
+) -> JxlRustStatus {
+    if decoder.is_null() || data_out.is_null() || len_out.is_null() {
+        return JxlRustStatus::Error;
+    }
+
+    let decoder = &*decoder;
+    match decoder.xmp_data() {
+        Some(xmp) => {
+            *data_out = xmp.as_ptr();
+            *len_out = xmp.len();
+            JxlRustStatus::Ok
+        }
+        None => JxlRustStatus::Error,
+    }
+}